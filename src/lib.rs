@@ -2,14 +2,14 @@ extern crate futures;
 
 /// A futures implementation of watched variables.
 pub mod watched_variables {
-    use futures::task::AtomicTask;
+    use futures::task::{self, Task};
     use futures::{Async, Poll, Stream};
 
     use std::convert::Infallible;
     use std::ops::Deref;
     use std::ops::DerefMut;
-    use std::sync::MutexGuard;
-    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
     #[derive(Clone)]
     pub enum StreamState {
@@ -18,38 +18,137 @@ pub mod watched_variables {
         Closed,
     }
 
+    /// A simple slab of parked tasks, one slot per live watcher. Keys are handed
+    /// out by `insert()` and reclaimed by `remove()` so that a given watcher always
+    /// writes into its own slot instead of clobbering another watcher's task.
+    #[derive(Default)]
+    struct WakerRegistry {
+        wakers: Vec<Option<Task>>,
+        free: Vec<usize>,
+    }
+
+    impl WakerRegistry {
+        fn insert(&mut self) -> usize {
+            match self.free.pop() {
+                Some(key) => {
+                    self.wakers[key] = None;
+                    key
+                }
+                None => {
+                    self.wakers.push(None);
+                    self.wakers.len() - 1
+                }
+            }
+        }
+
+        fn register(&mut self, key: usize) {
+            if let Some(slot) = self.wakers.get_mut(key) {
+                *slot = Some(task::current());
+            }
+        }
+
+        fn remove(&mut self, key: usize) {
+            if let Some(slot) = self.wakers.get_mut(key) {
+                *slot = None;
+                self.free.push(key);
+            }
+        }
+
+        fn notify_all(&self) {
+            for slot in self.wakers.iter() {
+                if let Some(task) = slot {
+                    task.notify();
+                }
+            }
+        }
+    }
+
+    /// The state shared between a `WatchedVariable` and all of its watchers: the
+    /// guarded value together with the registry of per-watcher tasks to wake. The
+    /// value sits behind an `RwLock`, so any number of read guards can be held at
+    /// once while writers stay exclusive.
+    pub struct Shared<T> {
+        pub state: RwLock<(T, StreamState)>,
+        /// Bumped on every mutable write-guard drop and every `force_ready()`. Watchers
+        /// compare it against the version they last observed to detect changes, so updates
+        /// that coalesce between two polls are never lost.
+        version: AtomicU64,
+        wakers: Mutex<WakerRegistry>,
+    }
+
+    impl<T> Shared<T> {
+        fn notify_all(&self) {
+            self.wakers.lock().unwrap().notify_all();
+        }
+
+        /// Records a change and wakes every watcher.
+        fn bump(&self) {
+            self.version.fetch_add(1, Ordering::SeqCst);
+            self.notify_all();
+        }
+    }
+
     /// This `futures::Stream` implementation will be notified whenever a `WatchedVariableAccessor` is dropped
     /// If the accessor was mutably derefenced, then a clone of the value after dropping will be sent upon polling
     ///
-    /// It implements Stream, where each frame will be a clone of its content (an Arc on the variable)
-    #[derive(Clone)]
+    /// It implements Stream, where each frame will be a clone of the shared state (an Arc on the variable).
+    /// Each watcher owns its own waker slot, so any number of watchers can be polled concurrently and all of
+    /// them are woken on a change.
     pub struct VariableWatcher<T> {
-        pub task: Arc<AtomicTask>,
-        pub content: Arc<Mutex<(T, StreamState)>>,
+        shared: Arc<Shared<T>>,
+        waker_key: usize,
+        last_seen: u64,
+    }
+
+    impl<T> Clone for VariableWatcher<T> {
+        fn clone(&self) -> Self {
+            let waker_key = self.shared.wakers.lock().unwrap().insert();
+            VariableWatcher {
+                shared: self.shared.clone(),
+                waker_key,
+                last_seen: self.last_seen,
+            }
+        }
+    }
+
+    impl<T> VariableWatcher<T> {
+        /// Borrows the current value without polling, returning the same read guard as
+        /// `WatchedVariable::read()`. The watcher's last-seen version is left untouched.
+        pub fn borrow(&self) -> WatchedVariableReadGuard<T> {
+            WatchedVariableReadGuard {
+                content: self.shared.state.read().unwrap(),
+            }
+        }
     }
 
     impl<T> Stream for VariableWatcher<T> {
-        type Item = Arc<Mutex<(T, StreamState)>>;
+        type Item = Arc<Shared<T>>;
         type Error = Infallible;
         fn poll(&mut self) -> Poll<Option<<Self as Stream>::Item>, <Self as Stream>::Error> {
-            self.task.register();
-            let mut guard = self.content.lock().unwrap();
-            match (guard.1).clone() {
-                StreamState::NotReady => Ok(Async::NotReady),
-                StreamState::Closed => Ok(Async::Ready(None)),
-                StreamState::Ready => {
-                    (*guard).1 = StreamState::NotReady;
-                    Ok(Async::Ready(Some(self.content.clone())))
-                }
+            self.shared.wakers.lock().unwrap().register(self.waker_key);
+            if let StreamState::Closed = self.shared.state.read().unwrap().1 {
+                return Ok(Async::Ready(None));
+            }
+            let version = self.shared.version.load(Ordering::SeqCst);
+            if version != self.last_seen {
+                self.last_seen = version;
+                Ok(Async::Ready(Some(self.shared.clone())))
+            } else {
+                Ok(Async::NotReady)
             }
         }
     }
 
+    impl<T> Drop for VariableWatcher<T> {
+        fn drop(&mut self) {
+            self.shared.wakers.lock().unwrap().remove(self.waker_key);
+        }
+    }
+
     /// A watched variable. Behaves similarly to a mutex, except that watchers obtained from its
     /// `get_watcher()` method will be notified upon mutable dereferencing.
     pub struct WatchedVariable<T> {
-        task: Arc<AtomicTask>,
-        content: Arc<Mutex<(T, StreamState)>>,
+        shared: Arc<Shared<T>>,
         counter: Arc<Mutex<u32>>,
     }
 
@@ -59,8 +158,7 @@ pub mod watched_variables {
                 *self.counter.lock().unwrap() += 1;
             }
             WatchedVariable {
-                task: self.task.clone(),
-                content: self.content.clone(),
+                shared: self.shared.clone(),
                 counter: self.counter.clone(),
             }
         }
@@ -71,32 +169,54 @@ pub mod watched_variables {
         /// on its watchers unless altered before the watchers are started
         pub fn from(value: T) -> WatchedVariable<T> {
             WatchedVariable {
-                task: Arc::new(AtomicTask::new()),
-                content: Arc::new(Mutex::new((value, StreamState::Ready))),
+                shared: Arc::new(Shared {
+                    state: RwLock::new((value, StreamState::Ready)),
+                    version: AtomicU64::new(1),
+                    wakers: Mutex::new(WakerRegistry::default()),
+                }),
                 counter: Arc::new(Mutex::new(1)),
             }
         }
 
         pub fn get_watcher(&self) -> VariableWatcher<T> {
+            let waker_key = self.shared.wakers.lock().unwrap().insert();
             VariableWatcher {
-                task: self.task.clone(),
-                content: self.content.clone(),
+                shared: self.shared.clone(),
+                waker_key,
+                last_seen: 0,
             }
         }
 
         /// Similar to Mutex::lock(), but the provided Accessor will trigger a `poll`
         /// upon `drop`, which will resolve to Ready if the accessor was accessed mutably.
+        ///
+        /// This is a shorthand for `write()`.
         pub fn lock(&self) -> WatchedVariableAccessor<T> {
+            self.write()
+        }
+
+        /// Takes an exclusive write accessor. Watchers are only notified on `drop` if the
+        /// accessor was dereferenced mutably; a write that only reads leaves them asleep.
+        pub fn write(&self) -> WatchedVariableAccessor<T> {
             WatchedVariableAccessor {
-                task: self.task.clone(),
-                content: self.content.lock().unwrap(),
+                shared: self.shared.clone(),
+                content: self.shared.state.write().unwrap(),
+                dirty: false,
+            }
+        }
+
+        /// Takes a shared read guard. Several read guards may be outstanding at once, and
+        /// dropping one never transitions the state to `Ready`, so pure observers don't
+        /// wake the watchers.
+        pub fn read(&self) -> WatchedVariableReadGuard<T> {
+            WatchedVariableReadGuard {
+                content: self.shared.state.read().unwrap(),
             }
         }
 
-        /// Allows to force ready upon the watcher.
+        /// Allows to force ready upon the watcher, bumping the version as if a mutation had occurred.
         pub fn force_ready(&self) {
-            self.content.lock().unwrap().1 = StreamState::Ready;
-            self.task.notify();
+            self.shared.bump();
         }
     }
 
@@ -105,22 +225,25 @@ pub mod watched_variables {
             let mut guard = self.counter.lock().unwrap();
             *guard -= 1;
             if *guard <= 0 {
-                let mut guard = self.content.lock().unwrap();
-                guard.1 = StreamState::Closed;
-                self.task.notify();
+                self.shared.state.write().unwrap().1 = StreamState::Closed;
+                self.shared.notify_all();
             }
         }
     }
 
-    /// Similar to a MutexGuard, but dropping it will also notify watchers associated with it.
+    /// Similar to a write-locked guard, but dropping it notifies the associated watchers
+    /// — but only if it was actually dereferenced mutably.
     pub struct WatchedVariableAccessor<'a, T> {
-        task: Arc<AtomicTask>,
-        content: MutexGuard<'a, (T, StreamState)>,
+        shared: Arc<Shared<T>>,
+        content: RwLockWriteGuard<'a, (T, StreamState)>,
+        dirty: bool,
     }
 
     impl<'a, T> Drop for WatchedVariableAccessor<'a, T> {
         fn drop(&mut self) {
-            self.task.notify();
+            if self.dirty {
+                self.shared.bump();
+            }
         }
     }
 
@@ -134,115 +257,322 @@ pub mod watched_variables {
     impl<'a, T> DerefMut for WatchedVariableAccessor<'a, T> {
         fn deref_mut(&mut self) -> &mut <Self as Deref>::Target {
             self.content.1 = StreamState::Ready;
+            self.dirty = true;
             return &mut self.content.0;
         }
     }
+
+    /// A read-only guard over a watched variable. It only implements `Deref`, and dropping it
+    /// never transitions the state to `Ready`, so read access can never wake a watcher.
+    pub struct WatchedVariableReadGuard<'a, T> {
+        content: RwLockReadGuard<'a, (T, StreamState)>,
+    }
+
+    impl<'a, T> Deref for WatchedVariableReadGuard<'a, T> {
+        type Target = T;
+        fn deref(&self) -> &<Self as Deref>::Target {
+            return &self.content.0;
+        }
+    }
 }
 
 /// A futures implementation of JS-like Promises.
 pub mod promises {
-    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
 
-    use futures::task::AtomicTask;
+    use futures::task::{self, Task};
     use futures::{Async, Future, Poll};
 
-    #[derive(Clone)]
-    enum PromiseState {
-        NotReady,
-        Resolved,
-        Rejected(String),
+    const STATE_PENDING: usize = 0;
+    const STATE_RESOLVED: usize = 1;
+    const STATE_REJECTED: usize = 2;
+
+    /// Per-handle parked tasks, keyed so each clone of a `PromiseHandle` keeps its
+    /// own slot and every pending handle is woken on resolve/reject.
+    #[derive(Default)]
+    struct WakerRegistry {
+        wakers: Vec<Option<Task>>,
+        free: Vec<usize>,
+    }
+
+    impl WakerRegistry {
+        fn insert(&mut self) -> usize {
+            match self.free.pop() {
+                Some(key) => {
+                    self.wakers[key] = None;
+                    key
+                }
+                None => {
+                    self.wakers.push(None);
+                    self.wakers.len() - 1
+                }
+            }
+        }
+
+        fn register(&mut self, key: usize) {
+            if let Some(slot) = self.wakers.get_mut(key) {
+                *slot = Some(task::current());
+            }
+        }
+
+        fn remove(&mut self, key: usize) {
+            if let Some(slot) = self.wakers.get_mut(key) {
+                *slot = None;
+                self.free.push(key);
+            }
+        }
+
+        fn notify_all(&self) {
+            for slot in self.wakers.iter() {
+                if let Some(task) = slot {
+                    task.notify();
+                }
+            }
+        }
+    }
+
+    /// The state shared between a `Promise` and all of its handles. The resolved
+    /// value is kept in place (never taken) so that every cloned handle observes it.
+    struct Inner<T> {
+        content: Mutex<Option<T>>,
+        reason: Mutex<Option<String>>,
+        state: AtomicUsize,
+        wakers: Mutex<WakerRegistry>,
+    }
+
+    impl<T> Inner<T> {
+        /// Transitions out of the pending state exactly once, returning `true` if this
+        /// call performed the transition. The shared state word lets handles created
+        /// after completion still observe the outcome.
+        fn settle(&self, target: usize) -> bool {
+            self.state
+                .compare_exchange(STATE_PENDING, target, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        }
     }
 
     /// The "sender" side of a Promise
     pub struct Promise<T> {
-        content: Arc<Mutex<Cell<Option<T>>>>,
-        state: Arc<Mutex<PromiseState>>,
-        task: Arc<AtomicTask>,
+        inner: Arc<Inner<T>>,
     }
 
     impl<T> Promise<T> {
         pub fn new() -> Self {
             Promise {
-                content: Arc::new(Mutex::new(Cell::new(None))),
-                state: Arc::new(Mutex::new(PromiseState::NotReady)),
-                task: Arc::new(AtomicTask::new()),
+                inner: Arc::new(Inner {
+                    content: Mutex::new(None),
+                    reason: Mutex::new(None),
+                    state: AtomicUsize::new(STATE_PENDING),
+                    wakers: Mutex::new(WakerRegistry::default()),
+                }),
             }
         }
 
         pub fn resolve(&self, value: T) {
-            let mut guard = self.state.lock().unwrap();
-            match (*guard).clone() {
-                PromiseState::NotReady => {
-                    self.content.lock().unwrap().set(Some(value));
-                    *guard = PromiseState::Resolved;
-                    self.task.notify();
-                }
-                _ => {
-                    panic!("Attempt to resolve an already finished promise");
+            let settled = {
+                let mut content = self.inner.content.lock().unwrap();
+                let settled = self.inner.settle(STATE_RESOLVED);
+                if settled {
+                    *content = Some(value);
                 }
+                settled
+            };
+            if settled {
+                self.inner.wakers.lock().unwrap().notify_all();
+            } else {
+                panic!("Attempt to resolve an already finished promise");
             }
         }
 
         pub fn reject(&self, message: String) {
-            let mut guard = self.state.lock().unwrap();
-            match (*guard).clone() {
-                PromiseState::NotReady => {
-                    *guard = PromiseState::Rejected(message);
-                    self.task.notify();
-                }
-                _ => {
-                    panic!("Attempt to reject an already finished promise");
+            let settled = {
+                let mut reason = self.inner.reason.lock().unwrap();
+                let settled = self.inner.settle(STATE_REJECTED);
+                if settled {
+                    *reason = Some(message);
                 }
+                settled
+            };
+            if settled {
+                self.inner.wakers.lock().unwrap().notify_all();
+            } else {
+                panic!("Attempt to reject an already finished promise");
             }
         }
 
         pub fn get_handle(&self) -> PromiseHandle<T> {
+            let waker_key = self.inner.wakers.lock().unwrap().insert();
             PromiseHandle {
-                content: self.content.clone(),
-                state: self.state.clone(),
-                task: self.task.clone(),
+                inner: self.inner.clone(),
+                waker_key,
             }
         }
     }
 
     impl<T> Drop for Promise<T> {
         fn drop(&mut self) {
-            let mut guard = self.state.lock().unwrap();
-            match (*guard).clone() {
-                PromiseState::NotReady => {
-                    *guard = PromiseState::Rejected("Promise Dropped".into());
-                    self.task.notify();
-                }
-                _ => {}
+            let mut reason = self.inner.reason.lock().unwrap();
+            if self.inner.settle(STATE_REJECTED) {
+                *reason = Some("Promise Dropped".into());
+                drop(reason);
+                self.inner.wakers.lock().unwrap().notify_all();
             }
         }
     }
 
-    /// The "receiver": a `Future` used to watch a `Promise`
-    #[derive(Clone)]
+    /// The "receiver": a `Future` used to watch a `Promise`. Every handle is woken on
+    /// completion and each `poll()` yields a clone of the resolved value, so any number
+    /// of handles behave like JS `.then` consumers of the same promise.
     pub struct PromiseHandle<T> {
-        content: Arc<Mutex<Cell<Option<T>>>>,
-        state: Arc<Mutex<PromiseState>>,
-        task: Arc<AtomicTask>,
+        inner: Arc<Inner<T>>,
+        waker_key: usize,
+    }
+
+    impl<T> Clone for PromiseHandle<T> {
+        fn clone(&self) -> Self {
+            let waker_key = self.inner.wakers.lock().unwrap().insert();
+            PromiseHandle {
+                inner: self.inner.clone(),
+                waker_key,
+            }
+        }
+    }
+
+    impl<T> Drop for PromiseHandle<T> {
+        fn drop(&mut self) {
+            self.inner.wakers.lock().unwrap().remove(self.waker_key);
+        }
     }
 
-    impl<T> Future for PromiseHandle<T> {
+    impl<T: Clone> Future for PromiseHandle<T> {
         type Item = T;
         type Error = String;
 
         fn poll(&mut self) -> Poll<<Self as Future>::Item, <Self as Future>::Error> {
-            match *self.state.lock().unwrap() {
-                PromiseState::NotReady => {
-                    self.task.register();
-                    Ok(Async::NotReady)
-                }
-                PromiseState::Rejected(ref reason) => Err(reason.clone()),
-                PromiseState::Resolved => match self.content.lock().unwrap().take() {
+            self.inner.wakers.lock().unwrap().register(self.waker_key);
+            match self.inner.state.load(Ordering::SeqCst) {
+                STATE_RESOLVED => match self.inner.content.lock().unwrap().clone() {
                     Some(value) => Ok(Async::Ready(value)),
                     None => Err("Promise resolved but value was None".into()),
                 },
+                STATE_REJECTED => Err(self
+                    .inner
+                    .reason
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .unwrap_or_else(|| "Promise rejected".into())),
+                _ => Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Structured cancellation built on top of the promise primitives.
+pub mod cancellation {
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use futures::{Async, Future, Poll};
+
+    use super::promises::{Promise, PromiseHandle};
+
+    struct TokenInner {
+        promise: Promise<()>,
+        cancelled: AtomicBool,
+        parent: Option<CancellationToken>,
+    }
+
+    /// The trigger side of a cancellation. Cloning a token yields another handle to the same
+    /// trigger; calling `cancel()` once fires every associated `cancelled()` future. Unlike
+    /// `Promise::resolve`, `cancel()` is idempotent: a second call is a no-op.
+    #[derive(Clone)]
+    pub struct CancellationToken {
+        inner: Arc<TokenInner>,
+    }
+
+    impl CancellationToken {
+        pub fn new() -> Self {
+            CancellationToken {
+                inner: Arc::new(TokenInner {
+                    promise: Promise::new(),
+                    cancelled: AtomicBool::new(false),
+                    parent: None,
+                }),
+            }
+        }
+
+        /// Cancels the token, waking every registered `cancelled()` future. Idempotent.
+        pub fn cancel(&self) {
+            if !self.inner.cancelled.swap(true, Ordering::SeqCst) {
+                self.inner.promise.resolve(());
+            }
+        }
+
+        /// Synchronously reports whether this token, or any of its ancestors, has been cancelled.
+        pub fn is_cancelled(&self) -> bool {
+            if self.inner.cancelled.load(Ordering::SeqCst) {
+                return true;
+            }
+            match &self.inner.parent {
+                Some(parent) => parent.is_cancelled(),
+                None => false,
+            }
+        }
+
+        /// Creates a token that is cancelled either explicitly or automatically when this token
+        /// (its parent) is cancelled.
+        pub fn child_token(&self) -> CancellationToken {
+            CancellationToken {
+                inner: Arc::new(TokenInner {
+                    promise: Promise::new(),
+                    cancelled: AtomicBool::new(false),
+                    parent: Some(self.clone()),
+                }),
+            }
+        }
+
+        /// Returns a `Future` that resolves once this token — or any ancestor — is cancelled.
+        pub fn cancelled(&self) -> CancelledFut {
+            let mut handles = Vec::new();
+            let mut current = Some(self);
+            while let Some(token) = current {
+                handles.push(token.inner.promise.get_handle());
+                current = token.inner.parent.as_ref();
+            }
+            CancelledFut {
+                token: self.clone(),
+                handles,
+            }
+        }
+    }
+
+    /// A `Future` that completes when its `CancellationToken` fires. It registers against the
+    /// token's promise and every ancestor's, so cancellation propagates down a task tree.
+    pub struct CancelledFut {
+        token: CancellationToken,
+        handles: Vec<PromiseHandle<()>>,
+    }
+
+    impl Future for CancelledFut {
+        type Item = ();
+        type Error = Infallible;
+
+        fn poll(&mut self) -> Poll<<Self as Future>::Item, <Self as Future>::Error> {
+            if self.token.is_cancelled() {
+                return Ok(Async::Ready(()));
+            }
+            // Poll every handle so this task is registered against all of them; any one
+            // resolving (or the promise being dropped) counts as cancellation.
+            for handle in self.handles.iter_mut() {
+                match handle.poll() {
+                    Ok(Async::NotReady) => {}
+                    _ => return Ok(Async::Ready(())),
+                }
             }
+            Ok(Async::NotReady)
         }
     }
 }